@@ -1,10 +1,25 @@
 use anyhow::{Context, Result};
 use log::{error, info, warn};
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchMode {
+    Events,
+    Poll,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Rendition {
+    width: u32,
+    height: u32,
+    bitrate: String,
+}
 
 #[derive(Debug, Clone)]
 struct ServiceConfig {
@@ -15,6 +30,12 @@ struct ServiceConfig {
     ffmpeg_preset: String,
     ffmpeg_crf: u32,
     audio_bitrate: String,
+    rendition_ladder: Vec<Rendition>,
+    ffprobe_path: String,
+    state_file: PathBuf,
+    watch_mode: WatchMode,
+    poll_interval: u64,
+    output_formats: Vec<String>,
 }
 
 impl Default for ServiceConfig {
@@ -34,48 +55,389 @@ impl Default for ServiceConfig {
             ffmpeg_preset: "medium".to_string(),
             ffmpeg_crf: 23,
             audio_bitrate: "128k".to_string(),
+            rendition_ladder: Vec::new(),
+            ffprobe_path: "ffprobe".to_string(),
+            state_file: PathBuf::new(),
+            watch_mode: WatchMode::Events,
+            poll_interval: 5,
+            output_formats: vec!["dash".to_string()],
         }
     }
 }
 
-fn load_config_from_env() -> ServiceConfig {
-    let watch_folder = std::env::var("WATCH_FOLDER")
-        .unwrap_or_else(|_| "/var/watch/videos".to_string());
-    
-    let video_extensions = std::env::var("VIDEO_EXTENSIONS")
-        .unwrap_or_else(|_| "mp4,avi,mkv,mov,wmv,flv".to_string())
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .collect();
-    
-    let ffmpeg_path = std::env::var("FFMPEG_PATH")
-        .unwrap_or_else(|_| "ffmpeg".to_string());
-    
-    let segment_duration = std::env::var("SEGMENT_DURATION")
-        .unwrap_or_else(|_| "4".to_string())
+/// Parses an `OUTPUT_FORMATS` string like `"dash,hls"` into lowercase format
+/// names. `convert_to_dash` always writes DASH and adds HLS playlists only
+/// when `"hls"` is in the list.
+fn parse_output_formats(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Parses a `RENDITION_LADDER` string like `"1920x1080:5000k,1280x720:2800k"`
+/// into the ordered list of rungs `convert_to_dash` should encode.
+fn parse_rendition_ladder(raw: &str) -> Vec<Rendition> {
+    raw.split(',')
+        .filter_map(|rung| {
+            let rung = rung.trim();
+            if rung.is_empty() {
+                return None;
+            }
+            let (dimensions, bitrate) = rung.split_once(':')?;
+            let (width, height) = dimensions.split_once('x')?;
+            Some(Rendition {
+                width: width.trim().parse().ok()?,
+                height: height.trim().parse().ok()?,
+                bitrate: bitrate.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Mirrors `ServiceConfig`, but every field is optional so a TOML file only
+/// needs to specify the knobs it wants to override.
+#[derive(Debug, Default, Deserialize)]
+struct FileConfig {
+    watch_folder: Option<PathBuf>,
+    video_extensions: Option<Vec<String>>,
+    ffmpeg_path: Option<String>,
+    segment_duration: Option<u32>,
+    ffmpeg_preset: Option<String>,
+    ffmpeg_crf: Option<u32>,
+    audio_bitrate: Option<String>,
+    rendition_ladder: Option<Vec<Rendition>>,
+    ffprobe_path: Option<String>,
+    state_file: Option<PathBuf>,
+    watch_mode: Option<String>,
+    poll_interval: Option<u64>,
+    output_formats: Option<Vec<String>>,
+}
+
+/// Reads `--config <path>` from the process arguments, falling back to
+/// `CONFIG_FILE`, so a config file can be set either way.
+fn config_file_path() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            match args.next() {
+                Some(path) => return Some(PathBuf::from(path)),
+                None => {
+                    warn!("--config flag given with no path; falling back to CONFIG_FILE");
+                    break;
+                }
+            }
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var("CONFIG_FILE").ok().map(PathBuf::from)
+}
+
+fn load_config_from_file(path: &Path) -> Result<FileConfig> {
+    let raw = std::fs::read_to_string(path)
+        .context(format!("Failed to read config file: {}", path.display()))?;
+    toml::from_str(&raw).context(format!("Failed to parse config file: {}", path.display()))
+}
+
+/// Merges a `FileConfig` layer over `config`, only overriding fields that
+/// were actually present in the file.
+fn merge_file_config(mut config: ServiceConfig, file: FileConfig) -> ServiceConfig {
+    if let Some(v) = file.watch_folder {
+        config.watch_folder = v;
+    }
+    if let Some(v) = file.video_extensions {
+        config.video_extensions = v;
+    }
+    if let Some(v) = file.ffmpeg_path {
+        config.ffmpeg_path = v;
+    }
+    if let Some(v) = file.segment_duration {
+        config.segment_duration = v;
+    }
+    if let Some(v) = file.ffmpeg_preset {
+        config.ffmpeg_preset = v;
+    }
+    if let Some(v) = file.ffmpeg_crf {
+        config.ffmpeg_crf = v;
+    }
+    if let Some(v) = file.audio_bitrate {
+        config.audio_bitrate = v;
+    }
+    if let Some(v) = file.rendition_ladder {
+        config.rendition_ladder = v;
+    }
+    if let Some(v) = file.ffprobe_path {
+        config.ffprobe_path = v;
+    }
+    if let Some(v) = file.state_file {
+        config.state_file = v;
+    }
+    if let Some(v) = file.watch_mode {
+        config.watch_mode = if v == "poll" { WatchMode::Poll } else { WatchMode::Events };
+    }
+    if let Some(v) = file.poll_interval {
+        config.poll_interval = v;
+    }
+    if let Some(v) = file.output_formats {
+        config.output_formats = v;
+    }
+    config
+}
+
+/// Overrides `config` with whichever environment variables are set, so env
+/// vars always win over both defaults and the config file.
+fn apply_env_overrides(mut config: ServiceConfig) -> ServiceConfig {
+    if let Ok(v) = std::env::var("WATCH_FOLDER") {
+        config.watch_folder = PathBuf::from(v);
+    }
+    if let Ok(v) = std::env::var("VIDEO_EXTENSIONS") {
+        config.video_extensions = v.split(',').map(|s| s.trim().to_string()).collect();
+    }
+    if let Ok(v) = std::env::var("FFMPEG_PATH") {
+        config.ffmpeg_path = v;
+    }
+    if let Ok(v) = std::env::var("SEGMENT_DURATION") {
+        if let Ok(v) = v.parse() {
+            config.segment_duration = v;
+        }
+    }
+    if let Ok(v) = std::env::var("FFMPEG_PRESET") {
+        config.ffmpeg_preset = v;
+    }
+    if let Ok(v) = std::env::var("FFMPEG_CRF") {
+        if let Ok(v) = v.parse() {
+            config.ffmpeg_crf = v;
+        }
+    }
+    if let Ok(v) = std::env::var("AUDIO_BITRATE") {
+        config.audio_bitrate = v;
+    }
+    if let Ok(v) = std::env::var("RENDITION_LADDER") {
+        config.rendition_ladder = parse_rendition_ladder(&v);
+    }
+    if let Ok(v) = std::env::var("FFPROBE_PATH") {
+        config.ffprobe_path = v;
+    }
+    if let Ok(v) = std::env::var("STATE_FILE") {
+        config.state_file = PathBuf::from(v);
+    }
+    if let Ok(v) = std::env::var("WATCH_MODE") {
+        config.watch_mode = if v == "poll" { WatchMode::Poll } else { WatchMode::Events };
+    }
+    if let Ok(v) = std::env::var("POLL_INTERVAL") {
+        if let Ok(v) = v.parse() {
+            config.poll_interval = v;
+        }
+    }
+    if let Ok(v) = std::env::var("OUTPUT_FORMATS") {
+        config.output_formats = parse_output_formats(&v);
+    }
+    config
+}
+
+/// Builds the effective `ServiceConfig` by layering built-in defaults, an
+/// optional TOML config file (`--config`/`CONFIG_FILE`), and environment
+/// variables, each layer overriding the one before it.
+fn load_config() -> Result<ServiceConfig> {
+    let mut config = ServiceConfig::default();
+
+    if let Some(path) = config_file_path() {
+        info!("Loading configuration file: {}", path.display());
+        let file_config = load_config_from_file(&path)?;
+        config = merge_file_config(config, file_config);
+    }
+
+    config = apply_env_overrides(config);
+
+    // state_file defaults off of watch_folder, so only compute it here if
+    // neither the config file nor the environment set it explicitly.
+    if config.state_file.as_os_str().is_empty() {
+        config.state_file = config.watch_folder.join(".dashtranscode-ledger.json");
+    }
+
+    Ok(config)
+}
+
+#[derive(Debug, Clone)]
+struct SourceMetadata {
+    video_codec: String,
+    audio_codec: String,
+    width: u32,
+    height: u32,
+}
+
+/// Runs `ffprobe` against the video stream and parses the codec name, width
+/// and height from the values it prints one per line; the `duration` entry
+/// is requested but not read.
+fn ffprobe_video(ffprobe_path: &str, video_path: &Path) -> Result<(String, u32, u32)> {
+    let output = Command::new(ffprobe_path)
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=codec_name,width,height,duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(video_path)
+        .output()
+        .context("Failed to execute ffprobe on video stream")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("ffprobe video inspection failed: {}", stderr);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let codec_name = lines.next().context("ffprobe returned no codec_name")?.to_string();
+    let width: u32 = lines
+        .next()
+        .context("ffprobe returned no width")?
         .parse()
-        .unwrap_or(4);
-    
-    let ffmpeg_preset = std::env::var("FFMPEG_PRESET")
-        .unwrap_or_else(|_| "medium".to_string());
-    
-    let ffmpeg_crf = std::env::var("FFMPEG_CRF")
-        .unwrap_or_else(|_| "23".to_string())
+        .context("ffprobe returned non-numeric width")?;
+    let height: u32 = lines
+        .next()
+        .context("ffprobe returned no height")?
         .parse()
-        .unwrap_or(23);
-    
-    let audio_bitrate = std::env::var("AUDIO_BITRATE")
-        .unwrap_or_else(|_| "128k".to_string());
-    
-    ServiceConfig {
-        watch_folder: PathBuf::from(watch_folder),
-        video_extensions,
-        ffmpeg_path,
-        segment_duration,
-        ffmpeg_preset,
-        ffmpeg_crf,
-        audio_bitrate,
+        .context("ffprobe returned non-numeric height")?;
+
+    Ok((codec_name, width, height))
+}
+
+/// Runs `ffprobe` against the audio stream and returns its codec name.
+fn ffprobe_audio(ffprobe_path: &str, video_path: &Path) -> Result<String> {
+    let output = Command::new(ffprobe_path)
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=codec_name",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(video_path)
+        .output()
+        .context("Failed to execute ffprobe on audio stream")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("ffprobe audio inspection failed: {}", stderr);
     }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let codec_name = stdout
+        .lines()
+        .next()
+        .context("ffprobe returned no audio codec_name")?
+        .to_string();
+
+    Ok(codec_name)
+}
+
+/// Probes `video_path` with ffprobe and returns its codecs and video dimensions.
+fn probe_source(ffprobe_path: &str, video_path: &Path) -> Result<SourceMetadata> {
+    let (video_codec, width, height) = ffprobe_video(ffprobe_path, video_path)?;
+    let audio_codec = ffprobe_audio(ffprobe_path, video_path)?;
+
+    Ok(SourceMetadata {
+        video_codec,
+        audio_codec,
+        width,
+        height,
+    })
+}
+
+/// Drops any rung that would upscale beyond `source`'s resolution, and
+/// decides whether the source can be stream-copied instead of re-encoded:
+/// only when no rung survives the filter (no ABR ladder to encode) and the
+/// source is already H.264/AAC.
+fn filter_ladder_and_can_stream_copy(
+    rendition_ladder: &[Rendition],
+    source: &SourceMetadata,
+) -> (Vec<Rendition>, bool) {
+    let ladder: Vec<Rendition> = rendition_ladder
+        .iter()
+        .filter(|rung| rung.width <= source.width && rung.height <= source.height)
+        .cloned()
+        .collect();
+
+    let can_stream_copy =
+        ladder.is_empty() && source.video_codec == "h264" && source.audio_codec == "aac";
+
+    (ladder, can_stream_copy)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LedgerEntry {
+    mtime: u64,
+    size: u64,
+    completed: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Ledger {
+    entries: HashMap<String, LedgerEntry>,
+}
+
+impl Ledger {
+    /// Loads the ledger from `state_file`, treating a missing or unreadable
+    /// file as an empty ledger so a fresh watch folder still works.
+    fn load(state_file: &Path) -> Ledger {
+        std::fs::read_to_string(state_file)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the ledger to a temp file and renames it into place, so a
+    /// crash mid-write never leaves `state_file` truncated or corrupt.
+    fn save(&self, state_file: &Path) -> Result<()> {
+        if let Some(parent) = state_file.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create ledger directory: {}", parent.display()))?;
+        }
+        let raw = serde_json::to_string_pretty(self).context("Failed to serialize ledger")?;
+        let tmp_path = state_file.with_extension("json.tmp");
+        std::fs::write(&tmp_path, raw)
+            .context(format!("Failed to write ledger: {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, state_file)
+            .context(format!("Failed to install ledger: {}", state_file.display()))
+    }
+}
+
+/// Guards the ledger's on-disk read-merge-write cycle so that two
+/// concurrently-processed files can't clobber each other's completed entry.
+static LEDGER_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Records a single ledger entry under `LEDGER_LOCK`, reloading the ledger
+/// from disk first so a concurrently-finished file's entry isn't lost.
+fn record_ledger_entry(state_file: &Path, path_key: String, entry: LedgerEntry) -> Result<()> {
+    let _guard = LEDGER_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut ledger = Ledger::load(state_file);
+    ledger.entries.insert(path_key, entry);
+    ledger.save(state_file)
+}
+
+/// Reads a file's mtime (seconds since the Unix epoch) and size, used to
+/// detect whether a source file has actually changed since it was last
+/// transcoded.
+fn file_fingerprint(path: &Path) -> Result<(u64, u64)> {
+    let metadata = std::fs::metadata(path)
+        .context(format!("Failed to read metadata for {}", path.display()))?;
+    let mtime = metadata
+        .modified()
+        .context("Failed to read mtime")?
+        .duration_since(UNIX_EPOCH)
+        .context("File mtime is before the Unix epoch")?
+        .as_secs();
+    Ok((mtime, metadata.len()))
 }
 
 fn is_video_file(path: &Path, extensions: &[String]) -> bool {
@@ -87,58 +449,124 @@ fn is_video_file(path: &Path, extensions: &[String]) -> bool {
     false
 }
 
-fn convert_to_dash(video_path: &Path, config: &ServiceConfig) -> Result<()> {
+/// Computes the per-file output directory and `manifest.mpd` path that
+/// `convert_to_dash` writes to, so callers can check for prior output
+/// without re-running the conversion.
+fn dash_output_paths(video_path: &Path) -> Result<(PathBuf, PathBuf)> {
     let file_stem = video_path
         .file_stem()
         .context("Invalid file name")?
         .to_str()
         .context("Invalid UTF-8 in file name")?;
-    
+
     let parent_dir = video_path.parent().context("No parent directory")?;
     let output_dir = parent_dir.join(file_stem);
-    
+    let manifest_path = output_dir.join("manifest.mpd");
+
+    Ok((output_dir, manifest_path))
+}
+
+fn convert_to_dash(video_path: &Path, config: &ServiceConfig) -> Result<()> {
+    let (output_dir, manifest_path) = dash_output_paths(video_path)?;
+
     info!("Converting {} to DASH format", video_path.display());
     info!("Output directory: {}", output_dir.display());
-    
+
     // Create output directory
     std::fs::create_dir_all(&output_dir)
         .context(format!("Failed to create output directory: {}", output_dir.display()))?;
-    
-    let manifest_path = output_dir.join("manifest.mpd");
+
     let init_seg = output_dir.join("init-stream$RepresentationID$.m4s");
     let media_seg = output_dir.join("chunk-stream$RepresentationID$-$Number%05d$.m4s");
-    
+
+    info!("Probing source with ffprobe...");
+    let source = probe_source(&config.ffprobe_path, video_path)
+        .context("Failed to probe source metadata")?;
+    info!(
+        "Source metadata: video_codec={}, audio_codec={}, resolution={}x{}",
+        source.video_codec, source.audio_codec, source.width, source.height
+    );
+
+    let (ladder, can_stream_copy) =
+        filter_ladder_and_can_stream_copy(&config.rendition_ladder, &source);
+
     info!("Creating DASH segments with FFmpeg...");
-    
+
     // Use FFmpeg's built-in DASH segmenter
+    let mut args: Vec<String> = vec![
+        "-i".to_string(),
+        video_path.to_str().unwrap().to_string(),
+    ];
+
+    if can_stream_copy {
+        info!("Source is already H.264/AAC; stream-copying instead of re-encoding");
+        args.extend(["-c:v".to_string(), "copy".to_string(), "-c:a".to_string(), "copy".to_string()]);
+    } else if ladder.is_empty() {
+        args.extend([
+            "-c:v".to_string(),
+            "libx264".to_string(),
+            "-preset".to_string(),
+            config.ffmpeg_preset.clone(),
+            "-crf".to_string(),
+            config.ffmpeg_crf.to_string(),
+            "-c:a".to_string(),
+            "aac".to_string(),
+            "-b:a".to_string(),
+            config.audio_bitrate.clone(),
+        ]);
+    } else {
+        for (i, rung) in ladder.iter().enumerate() {
+            args.extend([
+                "-map".to_string(),
+                "0:v:0".to_string(),
+                format!("-s:v:{i}"),
+                format!("{}x{}", rung.width, rung.height),
+                format!("-b:v:{i}"),
+                rung.bitrate.clone(),
+                format!("-preset:v:{i}"),
+                config.ffmpeg_preset.clone(),
+            ]);
+        }
+        args.extend([
+            "-map".to_string(),
+            "0:a:0".to_string(),
+            "-c:v".to_string(),
+            "libx264".to_string(),
+            "-c:a".to_string(),
+            "aac".to_string(),
+            "-b:a".to_string(),
+            config.audio_bitrate.clone(),
+            "-adaptation_sets".to_string(),
+            "id=0,streams=v id=1,streams=a".to_string(),
+        ]);
+    }
+
+    let emit_hls = config.output_formats.iter().any(|f| f == "hls");
+
+    args.extend([
+        "-f".to_string(),
+        "dash".to_string(),
+        "-seg_duration".to_string(),
+        config.segment_duration.to_string(),
+        "-use_template".to_string(),
+        "1".to_string(),
+        "-use_timeline".to_string(),
+        "1".to_string(),
+        "-init_seg_name".to_string(),
+        init_seg.file_name().unwrap().to_str().unwrap().to_string(),
+        "-media_seg_name".to_string(),
+        media_seg.file_name().unwrap().to_str().unwrap().to_string(),
+    ]);
+
+    if emit_hls {
+        info!("HLS output requested; emitting master/media playlists alongside the DASH manifest");
+        args.extend(["-hls_playlist".to_string(), "1".to_string()]);
+    }
+
+    args.push(manifest_path.to_str().unwrap().to_string());
+
     let ffmpeg_output = Command::new(&config.ffmpeg_path)
-        .args([
-            "-i",
-            video_path.to_str().unwrap(),
-            "-c:v",
-            "libx264",
-            "-preset",
-            &config.ffmpeg_preset,
-            "-crf",
-            &config.ffmpeg_crf.to_string(),
-            "-c:a",
-            "aac",
-            "-b:a",
-            &config.audio_bitrate,
-            "-f",
-            "dash",
-            "-seg_duration",
-            &config.segment_duration.to_string(),
-            "-use_template",
-            "1",
-            "-use_timeline",
-            "1",
-            "-init_seg_name",
-            init_seg.file_name().unwrap().to_str().unwrap(),
-            "-media_seg_name",
-            media_seg.file_name().unwrap().to_str().unwrap(),
-            manifest_path.to_str().unwrap(),
-        ])
+        .args(&args)
         .output()
         .context("Failed to execute FFmpeg")?;
     
@@ -155,39 +583,73 @@ fn convert_to_dash(video_path: &Path, config: &ServiceConfig) -> Result<()> {
     
     info!("Successfully converted {} to DASH format", video_path.display());
     info!("Manifest location: {}", manifest_path.display());
-    
+    if emit_hls {
+        info!("HLS master playlist: {}", output_dir.join("master.m3u8").display());
+    }
+
     Ok(())
 }
 
 fn process_video_file(path: PathBuf, config: &ServiceConfig) {
     info!("New video file detected: {}", path.display());
-    
+
     // Wait a bit to ensure file is completely written
     std::thread::sleep(Duration::from_secs(2));
-    
+
+    let path_key = path.to_string_lossy().to_string();
+    let fingerprint = match file_fingerprint(&path) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            error!("Failed to fingerprint {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let (mtime, size) = fingerprint;
+
+    let already_done = Ledger::load(&config.state_file)
+        .entries
+        .get(&path_key)
+        .map(|entry| entry.completed && entry.mtime == mtime && entry.size == size)
+        .unwrap_or(false);
+    let manifest_exists = dash_output_paths(&path)
+        .map(|(_, manifest_path)| manifest_path.exists())
+        .unwrap_or(false);
+
+    if already_done && manifest_exists {
+        info!("Skipping {}: ledger shows it is already up to date", path.display());
+        return;
+    }
+
     match convert_to_dash(&path, config) {
-        Ok(_) => info!("Successfully processed {}", path.display()),
+        Ok(_) => {
+            info!("Successfully processed {}", path.display());
+            let entry = LedgerEntry {
+                mtime,
+                size,
+                completed: true,
+            };
+            if let Err(e) = record_ledger_entry(&config.state_file, path_key, entry) {
+                warn!("Failed to persist ledger: {}", e);
+            }
+        }
         Err(e) => error!("Error processing {}: {}", path.display(), e),
     }
 }
 
-fn watch_folder(config: ServiceConfig) -> Result<()> {
+/// Spawns a thread to run `process_video_file` for `path` without blocking
+/// the watcher (event-based or polling) that discovered it.
+fn dispatch_video_file(path: PathBuf, config: &ServiceConfig) {
+    let config_clone = config.clone();
+    std::thread::spawn(move || {
+        process_video_file(path, &config_clone);
+    });
+}
+
+fn watch_folder_events(config: ServiceConfig) -> Result<()> {
     let watch_path = &config.watch_folder;
-    
-    if !watch_path.exists() {
-        warn!("Watch folder doesn't exist, creating: {}", watch_path.display());
-        std::fs::create_dir_all(watch_path)
-            .context("Failed to create watch folder")?;
-    }
-    
-    info!("Starting to watch folder: {}", watch_path.display());
-    info!("Watching for extensions: {:?}", config.video_extensions);
-    info!("FFmpeg preset: {}, CRF: {}", config.ffmpeg_preset, config.ffmpeg_crf);
-    info!("Audio bitrate: {}", config.audio_bitrate);
-    info!("Segment duration: {}s", config.segment_duration);
-    
+
     let (tx, rx) = channel();
-    
+
     let mut watcher: RecommendedWatcher = Watcher::new(
         move |res: Result<Event, notify::Error>| {
             if let Ok(event) = res {
@@ -196,40 +658,469 @@ fn watch_folder(config: ServiceConfig) -> Result<()> {
         },
         Config::default(),
     )?;
-    
+
     watcher.watch(watch_path, RecursiveMode::NonRecursive)?;
-    
-    info!("Folder watcher started successfully");
-    
+
+    info!("Folder watcher started successfully (event mode)");
+
     for event in rx {
         if let EventKind::Create(_) | EventKind::Modify(_) = event.kind {
             for path in event.paths {
                 if is_video_file(&path, &config.video_extensions) {
-                    let config_clone = config.clone();
-                    
-                    // Process in a separate thread to avoid blocking the watcher
-                    std::thread::spawn(move || {
-                        process_video_file(path, &config_clone);
-                    });
+                    dispatch_video_file(path, &config);
                 }
             }
         }
     }
-    
+
     Ok(())
 }
 
+/// A path's (mtime, size) fingerprint as observed in one folder scan.
+type ScanMap = HashMap<PathBuf, (u64, u64)>;
+
+/// Given the previous scan, the dispatch bookkeeping carried over from it,
+/// and the current scan, decides which paths have just stabilized (and so
+/// should be dispatched for transcoding) and returns the `dispatched` map
+/// to carry into the next tick — pruned of any path no longer present in
+/// `current_scan`.
+///
+/// Pulled out of `watch_folder_poll` so the stabilize/dispatch/prune logic
+/// can be unit tested without a real filesystem or watcher loop.
+fn poll_tick(previous_scan: &ScanMap, dispatched: &ScanMap, current_scan: &ScanMap) -> (Vec<PathBuf>, ScanMap) {
+    let mut next_dispatched: ScanMap = dispatched
+        .iter()
+        .filter(|(path, _)| current_scan.contains_key(*path))
+        .map(|(path, fingerprint)| (path.clone(), *fingerprint))
+        .collect();
+
+    let mut to_dispatch = Vec::new();
+    for (path, fingerprint) in current_scan {
+        let stabilized = previous_scan.get(path) == Some(fingerprint);
+        let already_dispatched = next_dispatched.get(path) == Some(fingerprint);
+        if stabilized && !already_dispatched {
+            to_dispatch.push(path.clone());
+            next_dispatched.insert(path.clone(), *fingerprint);
+        }
+    }
+
+    (to_dispatch, next_dispatched)
+}
+
+/// Repeatedly scans `watch_folder` for video files and enqueues any whose
+/// size/mtime has stabilized since the previous scan. This is a fallback
+/// for network/overlay filesystems where inotify-style events are
+/// unreliable or never fire.
+fn watch_folder_poll(config: ServiceConfig, poll_interval: u64) -> Result<()> {
+    info!(
+        "Folder watcher started successfully (poll mode, interval {}s)",
+        poll_interval
+    );
+
+    let mut previous_scan: ScanMap = HashMap::new();
+    let mut dispatched: ScanMap = HashMap::new();
+
+    loop {
+        std::thread::sleep(Duration::from_secs(poll_interval));
+
+        let mut current_scan: ScanMap = HashMap::new();
+        let entries = match std::fs::read_dir(&config.watch_folder) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to scan watch folder: {}", e);
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !is_video_file(&path, &config.video_extensions) {
+                continue;
+            }
+
+            let fingerprint = match file_fingerprint(&path) {
+                Ok(fingerprint) => fingerprint,
+                Err(e) => {
+                    warn!("Failed to fingerprint {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            current_scan.insert(path, fingerprint);
+        }
+
+        let (to_dispatch, next_dispatched) = poll_tick(&previous_scan, &dispatched, &current_scan);
+        for path in to_dispatch {
+            dispatch_video_file(path, &config);
+        }
+        dispatched = next_dispatched;
+
+        previous_scan = current_scan;
+    }
+}
+
+fn watch_folder(config: ServiceConfig) -> Result<()> {
+    let watch_path = &config.watch_folder;
+
+    if !watch_path.exists() {
+        warn!("Watch folder doesn't exist, creating: {}", watch_path.display());
+        std::fs::create_dir_all(watch_path)
+            .context("Failed to create watch folder")?;
+    }
+
+    info!("Starting to watch folder: {}", watch_path.display());
+    info!("Watching for extensions: {:?}", config.video_extensions);
+    info!("FFmpeg preset: {}, CRF: {}", config.ffmpeg_preset, config.ffmpeg_crf);
+    info!("Audio bitrate: {}", config.audio_bitrate);
+    info!("Segment duration: {}s", config.segment_duration);
+    if config.rendition_ladder.is_empty() {
+        info!("Rendition ladder: none (single representation)");
+    } else {
+        info!("Rendition ladder: {:?}", config.rendition_ladder);
+    }
+    info!("Watch mode: {:?}", config.watch_mode);
+    info!("Output formats: {:?}", config.output_formats);
+
+    match config.watch_mode {
+        WatchMode::Events => watch_folder_events(config),
+        WatchMode::Poll => {
+            let poll_interval = config.poll_interval;
+            watch_folder_poll(config, poll_interval)
+        }
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::init();
     
     info!("DASH Transcoding Service starting...");
-    info!("Loading configuration from environment variables");
-    
-    let config = load_config_from_env();
-    
+    info!("Loading configuration (defaults -> config file -> environment)");
+
+    let config = load_config()?;
+
     info!("Configuration loaded successfully");
-    
+
     watch_folder(config)?;
-    
+
     Ok(())
 }
+
+#[cfg(test)]
+mod rendition_ladder_tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_single_rung() {
+        let ladder = parse_rendition_ladder("1920x1080:5000k");
+        assert_eq!(ladder.len(), 1);
+        assert_eq!(ladder[0].width, 1920);
+        assert_eq!(ladder[0].height, 1080);
+        assert_eq!(ladder[0].bitrate, "5000k");
+    }
+
+    #[test]
+    fn parses_multiple_rungs_in_order() {
+        let ladder = parse_rendition_ladder("1920x1080:5000k,1280x720:2800k,854x480:1400k");
+        let resolutions: Vec<(u32, u32)> = ladder.iter().map(|r| (r.width, r.height)).collect();
+        assert_eq!(resolutions, vec![(1920, 1080), (1280, 720), (854, 480)]);
+    }
+
+    #[test]
+    fn trims_whitespace_and_skips_empty_and_malformed_rungs() {
+        let ladder = parse_rendition_ladder(" 1920x1080:5000k , , garbage , 1280x720:2800k ");
+        assert_eq!(ladder.len(), 2);
+        assert_eq!(ladder[0].bitrate, "5000k");
+        assert_eq!(ladder[1].bitrate, "2800k");
+    }
+
+    #[test]
+    fn empty_string_yields_empty_ladder() {
+        assert!(parse_rendition_ladder("").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod config_layering_tests {
+    use super::*;
+
+    #[test]
+    fn parse_output_formats_lowercases_and_trims() {
+        let formats = parse_output_formats(" DASH, Hls ,,");
+        assert_eq!(formats, vec!["dash".to_string(), "hls".to_string()]);
+    }
+
+    #[test]
+    fn parse_output_formats_empty_string_yields_empty_vec() {
+        assert!(parse_output_formats("").is_empty());
+    }
+
+    #[test]
+    fn merge_file_config_only_overrides_fields_present_in_the_file() {
+        let base = ServiceConfig::default();
+        let file = FileConfig {
+            ffmpeg_preset: Some("veryfast".to_string()),
+            ffmpeg_crf: Some(18),
+            ..Default::default()
+        };
+
+        let merged = merge_file_config(base.clone(), file);
+
+        assert_eq!(merged.ffmpeg_preset, "veryfast");
+        assert_eq!(merged.ffmpeg_crf, 18);
+        // Untouched fields keep their default value.
+        assert_eq!(merged.watch_folder, base.watch_folder);
+        assert_eq!(merged.audio_bitrate, base.audio_bitrate);
+    }
+
+    #[test]
+    fn merge_file_config_parses_watch_mode_string() {
+        let base = ServiceConfig::default();
+        let file = FileConfig {
+            watch_mode: Some("poll".to_string()),
+            ..Default::default()
+        };
+
+        let merged = merge_file_config(base, file);
+
+        assert_eq!(merged.watch_mode, WatchMode::Poll);
+    }
+
+    /// `apply_env_overrides` reads process-wide environment variables, so
+    /// these tests serialize on a private lock and clean up after
+    /// themselves to avoid interfering with each other.
+    static ENV_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn env_overrides_win_over_config_file_and_defaults() {
+        let _guard = ENV_TEST_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let base = ServiceConfig::default();
+        let file = FileConfig {
+            ffmpeg_preset: Some("veryfast".to_string()),
+            ..Default::default()
+        };
+        let config = merge_file_config(base, file);
+        assert_eq!(config.ffmpeg_preset, "veryfast");
+
+        std::env::set_var("FFMPEG_PRESET", "ultrafast");
+        let config = apply_env_overrides(config);
+        std::env::remove_var("FFMPEG_PRESET");
+
+        assert_eq!(config.ffmpeg_preset, "ultrafast");
+    }
+
+    #[test]
+    fn env_override_absent_leaves_config_file_value_in_place() {
+        let _guard = ENV_TEST_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        std::env::remove_var("AUDIO_BITRATE");
+
+        let base = ServiceConfig::default();
+        let file = FileConfig {
+            audio_bitrate: Some("192k".to_string()),
+            ..Default::default()
+        };
+        let config = merge_file_config(base, file);
+        let config = apply_env_overrides(config);
+
+        assert_eq!(config.audio_bitrate, "192k");
+    }
+}
+
+#[cfg(test)]
+mod ladder_filter_tests {
+    use super::*;
+
+    fn rendition(width: u32, height: u32) -> Rendition {
+        Rendition {
+            width,
+            height,
+            bitrate: "1000k".to_string(),
+        }
+    }
+
+    fn h264_aac_source(width: u32, height: u32) -> SourceMetadata {
+        SourceMetadata {
+            video_codec: "h264".to_string(),
+            audio_codec: "aac".to_string(),
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn all_rungs_below_source_resolution_survive_and_block_stream_copy() {
+        let ladder = vec![rendition(1280, 720), rendition(854, 480)];
+        let source = h264_aac_source(1920, 1080);
+
+        let (filtered, can_stream_copy) = filter_ladder_and_can_stream_copy(&ladder, &source);
+
+        assert_eq!(filtered.len(), 2);
+        assert!(!can_stream_copy);
+    }
+
+    #[test]
+    fn all_rungs_above_source_resolution_empty_out_and_allow_stream_copy() {
+        let ladder = vec![rendition(1920, 1080), rendition(1280, 720)];
+        let source = h264_aac_source(640, 360);
+
+        let (filtered, can_stream_copy) = filter_ladder_and_can_stream_copy(&ladder, &source);
+
+        assert!(filtered.is_empty());
+        assert!(can_stream_copy);
+    }
+
+    #[test]
+    fn mixed_rungs_keep_only_the_ones_at_or_below_source_resolution() {
+        let ladder = vec![rendition(1920, 1080), rendition(1280, 720), rendition(854, 480)];
+        let source = h264_aac_source(1280, 720);
+
+        let (filtered, can_stream_copy) = filter_ladder_and_can_stream_copy(&ladder, &source);
+
+        assert_eq!(
+            filtered.iter().map(|r| (r.width, r.height)).collect::<Vec<_>>(),
+            vec![(1280, 720), (854, 480)]
+        );
+        assert!(!can_stream_copy);
+    }
+
+    #[test]
+    fn non_h264_aac_source_never_stream_copies_even_with_an_empty_ladder() {
+        let ladder: Vec<Rendition> = Vec::new();
+        let source = SourceMetadata {
+            video_codec: "hevc".to_string(),
+            audio_codec: "aac".to_string(),
+            width: 1920,
+            height: 1080,
+        };
+
+        let (filtered, can_stream_copy) = filter_ladder_and_can_stream_copy(&ladder, &source);
+
+        assert!(filtered.is_empty());
+        assert!(!can_stream_copy);
+    }
+}
+
+#[cfg(test)]
+mod poll_tick_tests {
+    use super::*;
+
+    fn scan(entries: &[(&str, (u64, u64))]) -> ScanMap {
+        entries
+            .iter()
+            .map(|(path, fingerprint)| (PathBuf::from(path), *fingerprint))
+            .collect()
+    }
+
+    #[test]
+    fn first_scan_dispatches_nothing() {
+        let previous_scan = scan(&[]);
+        let dispatched = scan(&[]);
+        let current_scan = scan(&[("/watch/a.mp4", (100, 1000))]);
+
+        let (to_dispatch, next_dispatched) = poll_tick(&previous_scan, &dispatched, &current_scan);
+
+        assert!(to_dispatch.is_empty());
+        assert!(next_dispatched.is_empty());
+    }
+
+    #[test]
+    fn stabilized_file_is_dispatched_once() {
+        let previous_scan = scan(&[("/watch/a.mp4", (100, 1000))]);
+        let dispatched = scan(&[]);
+        let current_scan = scan(&[("/watch/a.mp4", (100, 1000))]);
+
+        let (to_dispatch, next_dispatched) = poll_tick(&previous_scan, &dispatched, &current_scan);
+
+        assert_eq!(to_dispatch, vec![PathBuf::from("/watch/a.mp4")]);
+        assert_eq!(next_dispatched.get(&PathBuf::from("/watch/a.mp4")), Some(&(100, 1000)));
+
+        // Next tick: still stable at the same fingerprint, already dispatched.
+        let (to_dispatch_again, _) = poll_tick(&previous_scan, &next_dispatched, &current_scan);
+        assert!(to_dispatch_again.is_empty());
+    }
+
+    #[test]
+    fn file_changed_after_dispatch_is_redispatched_once_stable_again() {
+        let previous_scan = scan(&[("/watch/a.mp4", (100, 1000))]);
+        let dispatched = scan(&[("/watch/a.mp4", (100, 1000))]);
+        // The file was rewritten: fingerprint changed but hasn't stabilized yet.
+        let current_scan = scan(&[("/watch/a.mp4", (200, 2000))]);
+
+        let (to_dispatch, next_dispatched) = poll_tick(&previous_scan, &dispatched, &current_scan);
+        assert!(to_dispatch.is_empty());
+
+        // It stabilizes on the next tick at the new fingerprint.
+        let previous_scan = current_scan.clone();
+        let (to_dispatch, _) = poll_tick(&previous_scan, &next_dispatched, &current_scan);
+        assert_eq!(to_dispatch, vec![PathBuf::from("/watch/a.mp4")]);
+    }
+
+    #[test]
+    fn disappeared_file_is_pruned_from_dispatched() {
+        let previous_scan = scan(&[("/watch/a.mp4", (100, 1000))]);
+        let dispatched = scan(&[("/watch/a.mp4", (100, 1000)), ("/watch/gone.mp4", (1, 1))]);
+        let current_scan = scan(&[("/watch/a.mp4", (100, 1000))]);
+
+        let (_, next_dispatched) = poll_tick(&previous_scan, &dispatched, &current_scan);
+
+        assert!(!next_dispatched.contains_key(&PathBuf::from("/watch/gone.mp4")));
+        assert_eq!(next_dispatched.len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod ledger_concurrency_tests {
+    use super::*;
+
+    /// Regression test for the read-merge-write race `record_ledger_entry`
+    /// guards against: several files finishing `convert_to_dash` around
+    /// the same time must each still have their entry survive in the
+    /// on-disk ledger, instead of the last writer clobbering the others.
+    #[test]
+    fn concurrent_record_ledger_entry_calls_do_not_lose_entries() {
+        let dir = std::env::temp_dir().join(format!(
+            "dashtranscode-ledger-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        let state_file = dir.join("ledger.json");
+
+        const WRITER_COUNT: u64 = 16;
+        let handles: Vec<_> = (0..WRITER_COUNT)
+            .map(|i| {
+                let state_file = state_file.clone();
+                std::thread::spawn(move || {
+                    record_ledger_entry(
+                        &state_file,
+                        format!("file-{i}.mp4"),
+                        LedgerEntry {
+                            mtime: i,
+                            size: i,
+                            completed: true,
+                        },
+                    )
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().expect("writer thread panicked").expect("record_ledger_entry failed");
+        }
+
+        let ledger = Ledger::load(&state_file);
+        assert_eq!(ledger.entries.len(), WRITER_COUNT as usize);
+        for i in 0..WRITER_COUNT {
+            let entry = ledger
+                .entries
+                .get(&format!("file-{i}.mp4"))
+                .unwrap_or_else(|| panic!("entry for file-{i}.mp4 was lost"));
+            assert_eq!(entry.mtime, i);
+            assert_eq!(entry.size, i);
+            assert!(entry.completed);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}